@@ -0,0 +1,40 @@
+//! Benchmarks for `ZXBorder`'s hot fill path
+extern crate criterion;
+extern crate rustzx_core;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rustzx_core::utils::Clocks;
+use rustzx_core::zx::machine::ZXMachine;
+use rustzx_core::zx::screen::border::{PixelFormat, ZXBorder};
+use rustzx_core::zx::screen::colors::{ZXColor, ZXPalette};
+
+/// worst case for the border beam loop: a `set_border` call on every
+/// scanline, forcing `fill_to` to run once per line instead of once per frame
+fn rainbow_border_frame(c: &mut Criterion) {
+    let colors = [
+        ZXColor::Black,
+        ZXColor::Blue,
+        ZXColor::Red,
+        ZXColor::Magenta,
+        ZXColor::Green,
+        ZXColor::Cyan,
+        ZXColor::Yellow,
+        ZXColor::White,
+    ];
+
+    c.bench_function("rainbow_border_frame", |b| {
+        let mut border = ZXBorder::new(ZXMachine::Sinclair48K, ZXPalette::default(), PixelFormat::Rgba8888);
+        b.iter(|| {
+            border.new_frame();
+            for line in 0..ZXMachine::Sinclair48K.specs().lines_per_frame() {
+                let clocks = Clocks((line * ZXMachine::Sinclair48K.specs().clocks_line as usize) as i32);
+                border.set_border(clocks, colors[line % colors.len()]);
+            }
+            black_box(border.texture());
+        })
+    });
+}
+
+criterion_group!(benches, rainbow_border_frame);
+criterion_main!(benches);
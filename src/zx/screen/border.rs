@@ -4,6 +4,112 @@ use utils::Clocks;
 use zx::constants::*;
 use zx::machine::*;
 
+/// Number of entries in the ULAplus CLUT
+const ULAPLUS_PALETTE_SIZE: usize = 64;
+
+/// ULAplus palette groups are 8 entries each; the border reads its colour
+/// from group 8 (the 8th group, 1-indexed), i.e. entries 56-63, keeping
+/// groups 1-7 (entries 0-55) free for ink/paper attribute palettes elsewhere.
+const ULAPLUS_BORDER_GROUP: usize = 7 * 8;
+
+/// All possible values of `ZXColor`, in declaration order. Used to build the
+/// `Indexed8` palette LUT without depending on the enum's internal layout.
+const ZX_COLORS: [ZXColor; 8] = [
+    ZXColor::Black,
+    ZXColor::Blue,
+    ZXColor::Red,
+    ZXColor::Magenta,
+    ZXColor::Green,
+    ZXColor::Cyan,
+    ZXColor::Yellow,
+    ZXColor::White,
+];
+
+/// Pixel encoding for `ZXBorder` output.
+///
+/// Frontends render to surfaces with different native pixel layouts; this
+/// lets each one select the layout it actually needs so `fill_to` writes
+/// pixels directly in that format instead of every frontend re-shuffling
+/// bytes after the fact.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel, R-G-B-A order
+    Rgba8888,
+    /// 4 bytes per pixel, A-R-G-B order
+    Argb8888,
+    /// 4 bytes per pixel, B-G-R-A order
+    Bgra8888,
+    /// 2 bytes per pixel, 5-6-5 bit RGB
+    Rgb565,
+    /// 1 byte per pixel, holding an index into `palette_lut()`, or into
+    /// `ulaplus_clut()` while ULAplus mode is enabled
+    Indexed8,
+}
+impl PixelFormat {
+    /// number of bytes used to encode a single pixel in this format
+    fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Rgba8888 | PixelFormat::Argb8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Indexed8 => 1,
+        }
+    }
+}
+
+/// packs `color`/`brightness` into the index used by the `Indexed8` format
+/// and by `palette_lut()`
+fn color_index(color: ZXColor, brightness: ZXBrightness) -> u8 {
+    let bright_bit = match brightness {
+        ZXBrightness::Normal => 0,
+        ZXBrightness::Bright => 1,
+    };
+    (color as u8) | (bright_bit << 3)
+}
+
+/// encodes `rgba` in `format`, returning the encoded bytes in the low
+/// `format.bytes_per_pixel()` positions of the result. `index` is used
+/// verbatim for `Indexed8`, which carries a palette index rather than colour.
+fn encode_color(format: PixelFormat, rgba: [u8; 4], index: u8) -> [u8; 4] {
+    match format {
+        PixelFormat::Rgba8888 => rgba,
+        PixelFormat::Argb8888 => [rgba[3], rgba[0], rgba[1], rgba[2]],
+        PixelFormat::Bgra8888 => [rgba[2], rgba[1], rgba[0], rgba[3]],
+        PixelFormat::Rgb565 => {
+            let r = (rgba[0] as u16 >> 3) & 0x1F;
+            let g = (rgba[1] as u16 >> 2) & 0x3F;
+            let b = (rgba[2] as u16 >> 3) & 0x1F;
+            let packed = (r << 11) | (g << 5) | b;
+            // native-endian: `fill_span` reconstitutes this via
+            // `u16::from_ne_bytes`, so it must match here to round-trip on
+            // both little- and big-endian targets.
+            let [lo, hi] = packed.to_ne_bytes();
+            [lo, hi, 0, 0]
+        }
+        PixelFormat::Indexed8 => [index, 0, 0, 0],
+    }
+}
+
+/// fills a byte-level edge left unaligned by `align_to_mut`, cycling through
+/// `pixel` (one pixel's worth of encoded bytes)
+fn fill_unaligned_edge(bytes: &mut [u8], pixel: &[u8]) {
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = pixel[i % pixel.len()];
+    }
+}
+
+/// decodes a ULAplus palette byte (G3R3B2) into RGBA8888
+fn decode_g3r3b2(value: u8) -> [u8; 4] {
+    let g = (value >> 5) & 0x07;
+    let r = (value >> 2) & 0x07;
+    let b = value & 0x03;
+    [
+        (r as u16 * 255 / 7) as u8,
+        (g as u16 * 255 / 7) as u8,
+        (b as u16 * 255 / 3) as u8,
+        255,
+    ]
+}
+
 /// Internal struct, which contains information about beam position and color
 #[derive(Clone, Copy)]
 struct BeamInfo {
@@ -39,21 +145,33 @@ impl BeamInfo {
 pub struct ZXBorder {
     machine: ZXMachine,
     palette: ZXPalette,
-    buffer: Box<[u8; PIXEL_COUNT * BYTES_PER_PIXEL]>,
+    pixel_format: PixelFormat,
+    buffer: Vec<u8>,
     beam_last: BeamInfo,
     border_changed: bool,
     beam_block: bool,
+    dirty_rect: Option<(usize, usize, usize, usize)>,
+    ulaplus_enabled: bool,
+    ulaplus_clut: [u8; ULAPLUS_PALETTE_SIZE],
+    capture_enabled: bool,
+    trace: Vec<(Clocks, ZXColor)>,
 }
 impl ZXBorder {
-    /// Returns new instance of border device
-    pub fn new(machine: ZXMachine, palette: ZXPalette) -> ZXBorder {
+    /// Returns new instance of border device, encoding pixels in `pixel_format`
+    pub fn new(machine: ZXMachine, palette: ZXPalette, pixel_format: PixelFormat) -> ZXBorder {
         ZXBorder {
             machine: machine,
             palette: palette,
-            buffer: Box::new([0; PIXEL_COUNT * BYTES_PER_PIXEL]),
+            pixel_format: pixel_format,
+            buffer: vec![0; PIXEL_COUNT * pixel_format.bytes_per_pixel()],
             beam_last: BeamInfo::first_pixel(ZXColor::White),
             border_changed: true,
             beam_block: false,
+            dirty_rect: None,
+            ulaplus_enabled: false,
+            ulaplus_clut: [0; ULAPLUS_PALETTE_SIZE],
+            capture_enabled: false,
+            trace: Vec::new(),
         }
     }
 
@@ -94,37 +212,156 @@ impl ZXBorder {
 
     /// fills pixels from last pos to passed by arguments with
     fn fill_to(&mut self, line: usize, pixel: usize) {
+        self.fill_to_impl(line, pixel, true);
+    }
+
+    /// same as `fill_to`, but only extends the dirty rectangle when
+    /// `track_dirty` is set. Used to repaint the static-border tail span in
+    /// `new_frame` without reporting it as a change.
+    fn fill_to_impl(&mut self, line: usize, pixel: usize, track_dirty: bool) {
         let last = self.beam_last;
-        let color_array = self.palette.get_rgba(last.color, ZXBrightness::Normal);
-        // fill pixels
-        for p in (last.line * SCREEN_WIDTH + last.pixel)..(line * SCREEN_WIDTH + pixel) {
-            // all 4 bytes of color
-            for b in 0..BYTES_PER_PIXEL {
-                self.buffer[p * BYTES_PER_PIXEL + b] = color_array[b];
+        let start = last.line * SCREEN_WIDTH + last.pixel;
+        let end = line * SCREEN_WIDTH + pixel;
+        if start >= end {
+            return;
+        }
+        self.fill_span(start, end, last.color);
+        if track_dirty {
+            self.mark_dirty(start, end);
+        }
+    }
+
+    /// fills the pixel span `[start, end)` with `color`. The colour is
+    /// encoded once and then written a whole pixel word at a time (instead
+    /// of byte-by-byte), so `slice::fill` can vectorize the common case of
+    /// tens of thousands of pixels sharing one colour.
+    fn fill_span(&mut self, start: usize, end: usize, color: ZXColor) {
+        let rgba = self.border_rgba(color);
+        let index = self.indexed_pixel_index(color);
+        let encoded = encode_color(self.pixel_format, rgba, index);
+        match self.pixel_format.bytes_per_pixel() {
+            4 => {
+                let bytes = &mut self.buffer[start * 4..end * 4];
+                // SAFETY: `u32` has no invalid bit patterns, so reinterpreting
+                // bytes as `u32` is sound regardless of alignment; `align_to_mut`
+                // checks the base pointer's alignment at runtime and leaves any
+                // unaligned edge in `prefix`/`suffix` instead of `words`.
+                let (prefix, words, suffix) = unsafe { bytes.align_to_mut::<u32>() };
+                // `words` (and `suffix` after it) start `prefix.len()` bytes
+                // into the span, so they start at that byte of the pixel
+                let mut phased = encoded;
+                phased.rotate_left(prefix.len() % 4);
+                fill_unaligned_edge(prefix, &encoded[..4]);
+                words.fill(u32::from_ne_bytes(phased));
+                fill_unaligned_edge(suffix, &phased[..4]);
+            }
+            2 => {
+                let bytes = &mut self.buffer[start * 2..end * 2];
+                // SAFETY: see the 4-byte case above, with `u16` words.
+                let (prefix, words, suffix) = unsafe { bytes.align_to_mut::<u16>() };
+                let mut phased = [encoded[0], encoded[1]];
+                phased.rotate_left(prefix.len() % 2);
+                fill_unaligned_edge(prefix, &encoded[..2]);
+                words.fill(u16::from_ne_bytes(phased));
+                fill_unaligned_edge(suffix, &phased);
             }
+            _ => {
+                self.buffer[start..end].fill(encoded[0]);
+            }
+        }
+    }
+
+    /// resolves the `Indexed8` output byte for `color`: an index into
+    /// `ulaplus_clut()` while ULAplus mode is active (so `Indexed8` frontends
+    /// stay in sync with `border_rgba`'s source of truth), or into
+    /// `palette_lut()` otherwise
+    fn indexed_pixel_index(&self, color: ZXColor) -> u8 {
+        if self.ulaplus_enabled {
+            (ULAPLUS_BORDER_GROUP + color as usize) as u8
+        } else {
+            color_index(color, ZXBrightness::Normal)
         }
     }
 
+    /// resolves the RGBA colour for `color`, through the ULAplus CLUT when
+    /// ULAplus mode is active, or the legacy fixed `ZXPalette` otherwise
+    fn border_rgba(&self, color: ZXColor) -> [u8; 4] {
+        if self.ulaplus_enabled {
+            decode_g3r3b2(self.ulaplus_clut[ULAPLUS_BORDER_GROUP + color as usize])
+        } else {
+            self.palette.get_rgba(color, ZXBrightness::Normal)
+        }
+    }
+
+    /// extends the accumulated dirty rectangle to cover the pixel span `[start, end)`
+    fn mark_dirty(&mut self, start: usize, end: usize) {
+        let row_start = start / SCREEN_WIDTH;
+        let row_end = (end - 1) / SCREEN_WIDTH;
+        let (x, w) = if row_start == row_end {
+            (start % SCREEN_WIDTH, end - start)
+        } else {
+            (0, SCREEN_WIDTH)
+        };
+        let rect = (x, row_start, w, row_end - row_start + 1);
+        self.dirty_rect = Some(match self.dirty_rect {
+            None => rect,
+            Some((dx, dy, dw, dh)) => {
+                let x0 = dx.min(rect.0);
+                let y0 = dy.min(rect.1);
+                let x1 = (dx + dw).max(rect.0 + rect.2);
+                let y1 = (dy + dh).max(rect.1 + rect.3);
+                (x0, y0, x1 - x0, y1 - y0)
+            }
+        });
+    }
+
     /// starts new frame
     pub fn new_frame(&mut self) {
         // if border was not changed during prev frame then force change color of whole border
         if !self.border_changed {
             self.beam_last.reset();
         }
-        // fill to end of screen if not already filled
+        // fill to end of screen if not already filled. When the border never
+        // changed this frame and the buffer already holds its colour
+        // everywhere, this repaint changes no pixel, so it is not reported
+        // as dirty.
         if !self.beam_block {
-            self.fill_to(SCREEN_HEIGHT - 1, SCREEN_WIDTH);
+            let color = self.beam_last.color;
+            let bpp = self.pixel_format.bytes_per_pixel();
+            let encoded = encode_color(
+                self.pixel_format,
+                self.border_rgba(color),
+                self.indexed_pixel_index(color),
+            );
+            let unchanged =
+                !self.border_changed && self.buffer.chunks(bpp).all(|p| p == &encoded[..bpp]);
+            self.fill_to_impl(SCREEN_HEIGHT - 1, SCREEN_WIDTH, !unchanged);
         }
         // move beam to begin and reset flags
         self.beam_last.reset();
         self.border_changed = false;
         self.beam_block = false;
+        self.trace.clear();
+        // `dirty_rect` is intentionally left alone here: it accumulates
+        // across frames until `take_dirty_rect()` reads it, which clears it
+        // via `Option::take`. Resetting it here would discard the region
+        // painted by the tail fill above before any caller could observe it.
+    }
+
+    /// Takes the bounding rectangle `(x, y, w, h)`, in pixels, of the border
+    /// region overwritten since the last call, or `None` if nothing changed.
+    /// Lets a frontend re-upload only the dirty span of its border texture.
+    pub fn take_dirty_rect(&mut self) -> Option<(usize, usize, usize, usize)> {
+        self.dirty_rect.take()
     }
 
     /// changes color of border
     pub fn set_border(&mut self, clocks: Clocks, color: ZXColor) {
         // border updated during frame
         self.border_changed = true;
+        if self.capture_enabled {
+            self.trace.push((clocks, color));
+        }
         let (line, pixel, frame_end) = self.next_border_pixel(clocks);
         if !self.beam_block {
             // if not first pixel then update
@@ -137,8 +374,152 @@ impl ZXBorder {
         self.beam_last = BeamInfo::new(line, pixel, color);
     }
 
-    /// Returns reference to texture
+    /// Enables or disables ULAplus border colour resolution. While enabled,
+    /// `fill_to` resolves the border colour through the ULAplus CLUT instead
+    /// of the legacy fixed `ZXPalette`.
+    pub fn set_ulaplus_enabled(&mut self, enabled: bool) {
+        self.ulaplus_enabled = enabled;
+    }
+
+    /// Writes `value` (packed G3R3B2) into ULAplus CLUT entry `index`.
+    /// Beam-timed like `set_border`: pixels already drawn this frame keep
+    /// their prior colour, only pixels drawn after `clocks` pick up the
+    /// updated entry. Out-of-range indices (`>= 64`) are ignored.
+    pub fn set_ulaplus_palette(&mut self, clocks: Clocks, index: usize, value: u8) {
+        if index >= ULAPLUS_PALETTE_SIZE {
+            return;
+        }
+        // border updated during frame, so `new_frame` keeps what was drawn
+        self.border_changed = true;
+        let (line, pixel, frame_end) = self.next_border_pixel(clocks);
+        if !self.beam_block {
+            if frame_end {
+                self.fill_to(SCREEN_HEIGHT - 1, SCREEN_WIDTH);
+                self.beam_block = true;
+            }
+            self.fill_to(line, pixel);
+        }
+        self.ulaplus_clut[index] = value;
+        self.beam_last = BeamInfo::new(line, pixel, self.beam_last.color);
+    }
+
+    /// Returns reference to texture, encoded in the `PixelFormat` this
+    /// border was constructed with
     pub fn texture(&self) -> &[u8] {
-        &(*self.buffer)
+        &self.buffer
+    }
+
+    /// Returns the 16-entry RGBA palette lookup table backing the
+    /// `Indexed8` format, indexed by `(color as u8) | (bright as u8) << 3`.
+    /// Frontends using `Indexed8` expand `texture()` indices through this
+    /// table (e.g. in a shader) instead of receiving pre-expanded RGBA.
+    ///
+    /// Only valid while ULAplus is disabled: while it's enabled, `texture()`
+    /// instead emits indices into `ulaplus_clut()` (see that method).
+    pub fn palette_lut(&self) -> [[u8; 4]; 16] {
+        let mut lut = [[0u8; 4]; 16];
+        for &color in ZX_COLORS.iter() {
+            for &brightness in [ZXBrightness::Normal, ZXBrightness::Bright].iter() {
+                let idx = color_index(color, brightness) as usize;
+                lut[idx] = self.palette.get_rgba(color, brightness);
+            }
+        }
+        lut
+    }
+
+    /// Returns the raw ULAplus CLUT (64 entries, packed G3R3B2). While
+    /// ULAplus is enabled, `texture()` emits indices into this table instead
+    /// of `palette_lut()` for the `Indexed8` format, so frontends decode
+    /// G3R3B2 themselves (e.g. in a shader).
+    pub fn ulaplus_clut(&self) -> &[u8; ULAPLUS_PALETTE_SIZE] {
+        &self.ulaplus_clut
+    }
+
+    /// Enables or disables per-clock `set_border` capture. The trace only
+    /// ever covers the current frame: enabling it clears any previous
+    /// contents, and `new_frame` clears it again once the frame ends.
+    pub fn set_capture_enabled(&mut self, enabled: bool) {
+        self.capture_enabled = enabled;
+        self.trace.clear();
+    }
+
+    /// Returns the `(clocks, color)` pairs passed to `set_border` so far
+    /// this frame, in call order, while capture is enabled. Lets tooling
+    /// record and diff the exact beam timeline of multicolour border demos.
+    pub fn border_trace(&self) -> &[(Clocks, ZXColor)] {
+        &self.trace
+    }
+
+    /// Rebuilds a border framebuffer deterministically by replaying a
+    /// captured `border_trace()` through a fresh `ZXBorder`, without
+    /// depending on any other emulator state. Used to diff a beam-timing
+    /// trace against a reference capture in a golden-image test.
+    pub fn render_trace(
+        machine: ZXMachine,
+        palette: ZXPalette,
+        pixel_format: PixelFormat,
+        trace: &[(Clocks, ZXColor)],
+    ) -> Vec<u8> {
+        let mut border = ZXBorder::new(machine, palette, pixel_format);
+        for &(clocks, color) in trace {
+            border.set_border(clocks, color);
+        }
+        border.new_frame();
+        border.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_border() -> ZXBorder {
+        ZXBorder::new(
+            ZXMachine::Sinclair48K,
+            ZXPalette::default(),
+            PixelFormat::Rgba8888,
+        )
+    }
+
+    #[test]
+    fn take_dirty_rect_reports_change_once() {
+        let mut border = new_border();
+        // first frame: constructor starts with `border_changed = true`, so
+        // the initial full-screen paint is expected to be reported dirty.
+        border.new_frame();
+        assert!(border.take_dirty_rect().is_some());
+        assert!(border.take_dirty_rect().is_none());
+
+        // second frame: nothing changed, so the tail repaint in `new_frame`
+        // must not be reported as dirty.
+        border.new_frame();
+        assert!(border.take_dirty_rect().is_none());
+
+        // changing the border colour mid-frame should be picked up once
+        // `new_frame` closes out the frame, and only once.
+        border.set_border(Clocks(1000), ZXColor::Red);
+        border.new_frame();
+        assert!(border.take_dirty_rect().is_some());
+        assert!(border.take_dirty_rect().is_none());
+    }
+
+    #[test]
+    fn render_trace_reproduces_live_texture() {
+        let mut border = new_border();
+        border.set_capture_enabled(true);
+        let colors = [ZXColor::Blue, ZXColor::Red, ZXColor::Green, ZXColor::White];
+        for (i, &color) in colors.iter().enumerate() {
+            border.set_border(Clocks((i * 10_000) as i32), color);
+        }
+        let trace: Vec<_> = border.border_trace().to_vec();
+        border.new_frame();
+
+        let replayed = ZXBorder::render_trace(
+            ZXMachine::Sinclair48K,
+            ZXPalette::default(),
+            PixelFormat::Rgba8888,
+            &trace,
+        );
+        assert_eq!(border.texture(), &replayed[..]);
     }
 }